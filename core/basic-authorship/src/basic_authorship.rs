@@ -151,6 +151,19 @@ impl<C, A> consensus_common::Environment<<C as AuthoringApi>::Block> for Propose
 	}
 }
 
+/// What happened to the ready transactions while a block was being built.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ProposalStats {
+	/// Transactions pushed to the block.
+	included: usize,
+	/// Transactions skipped because the block seemed full.
+	skipped: usize,
+	/// Transactions removed from the pool as invalid.
+	culled: usize,
+	/// Time it took to assemble the block.
+	elapsed: time::Duration,
+}
+
 /// The proposer logic.
 pub struct Proposer<Block: BlockT, C, A: txpool::ChainApi> {
 	client: Arc<C>,
@@ -174,9 +187,10 @@ impl<Block, C, A> consensus_common::Proposer<<C as AuthoringApi>::Block> for Pro
 	fn propose(&self, inherent_data: InherentData, max_duration: time::Duration)
 		-> Result<<C as AuthoringApi>::Block, error::Error>
 	{
+		let started = (self.now)();
 		// leave some time for evaluation and block finalisation (33%)
-		let deadline = (self.now)() + max_duration - max_duration / 3;
-		self.propose_with(inherent_data, deadline)
+		let deadline = started + max_duration - max_duration / 3;
+		self.propose_with(inherent_data, started, deadline).map(|(block, _)| block)
 	}
 }
 
@@ -187,61 +201,20 @@ impl<Block, C, A> Proposer<Block, C, A>	where
 	A: txpool::ChainApi<Block=Block>,
 	client::error::Error: From<<C as AuthoringApi>::Error>,
 {
-	fn propose_with(&self, inherent_data: InherentData, deadline: time::Instant)
-		-> Result<<C as AuthoringApi>::Block, error::Error>
+	fn propose_with(&self, inherent_data: InherentData, started: time::Instant, deadline: time::Instant)
+		-> Result<(<C as AuthoringApi>::Block, ProposalStats), error::Error>
 	{
 		use runtime_primitives::traits::BlakeTwo256;
 
-		/// If the block is full we will attempt to push at most
-		/// this number of transactions before quitting for real.
-		/// It allows us to increase block utilisation.
-		const MAX_SKIPPED_TRANSACTIONS: usize = 8;
-
+		let mut stats = ProposalStats::default();
 		let block = self.client.build_block(
 			&self.parent_id,
 			inherent_data,
-			|block_builder| {
-				let mut is_first = true;
-				let mut skipped = 0;
-				let mut unqueue_invalid = Vec::new();
-				let pending_iterator = self.transaction_pool.ready();
-
-				for pending in pending_iterator {
-					if (self.now)() > deadline {
-						debug!("Consensus deadline reached when pushing block transactions, proceeding with proposing.");
-						break;
-					}
-
-					match block_builder.push_extrinsic(pending.data.clone()) {
-						Ok(()) => {
-							debug!("[{:?}] Pushed to the block.", pending.hash);
-						}
-						Err(error::Error(error::ErrorKind::ApplyExtrinsicFailed(ApplyError::FullBlock), _)) => {
-							if is_first {
-								debug!("[{:?}] Invalid transaction: FullBlock on empty block", pending.hash);
-								unqueue_invalid.push(pending.hash.clone());
-							} else if skipped < MAX_SKIPPED_TRANSACTIONS {
-								skipped += 1;
-								debug!(
-									"Block seems full, but will try {} more transactions before quitting.",
-									MAX_SKIPPED_TRANSACTIONS - skipped
-								);
-							} else {
-								debug!("Block is full, proceed with proposing.");
-								break;
-							}
-						}
-						Err(e) => {
-							debug!("[{:?}] Invalid transaction: {}", pending.hash, e);
-							unqueue_invalid.push(pending.hash.clone());
-						}
-					}
-
-					is_first = false;
-				}
+			|block_builder| stats = self.push_ready(block_builder, deadline),
+		)?;
 
-				self.transaction_pool.remove_invalid(&unqueue_invalid);
-			})?;
+		stats.elapsed = (self.now)() - started;
+		debug!("Block on top of {} (#{}) assembled in {:?}", self.parent_hash, self.parent_number, stats.elapsed);
 
 		info!("Prepared block for proposing at {} [hash: {:?}; parent_hash: {}; extrinsics: [{}]]",
 			block.header().number(),
@@ -256,6 +229,10 @@ impl<Block, C, A> Proposer<Block, C, A>	where
 		telemetry!(CONSENSUS_INFO; "prepared_block_for_proposing";
 			"number" => ?block.header().number(),
 			"hash" => ?<<C as AuthoringApi>::Block as BlockT>::Hash::from(block.header().hash()),
+			"elapsed_ms" => ?(stats.elapsed.as_secs() * 1000 + u64::from(stats.elapsed.subsec_millis())),
+			"included" => stats.included,
+			"skipped" => stats.skipped,
+			"culled" => stats.culled,
 		);
 
 		let substrate_block = Decode::decode(&mut block.encode().as_slice())
@@ -267,7 +244,68 @@ impl<Block, C, A> Proposer<Block, C, A>	where
 			self.parent_number,
 		).is_ok());
 
-		Ok(substrate_block)
+		Ok((substrate_block, stats))
+	}
+
+	/// Push ready transactions until the block is full or the deadline is reached.
+	///
+	/// Transactions found to be invalid are removed from the pool.
+	fn push_ready(&self, block_builder: &mut BlockBuilder<Block>, deadline: time::Instant) -> ProposalStats {
+		/// If the block is full we will attempt to push at most
+		/// this number of transactions before quitting for real.
+		/// It allows us to increase block utilisation.
+		const MAX_SKIPPED_TRANSACTIONS: usize = 8;
+
+		let mut is_first = true;
+		let mut skipped = 0;
+		let mut included = 0;
+		let mut unqueue_invalid = Vec::new();
+		let pending_iterator = self.transaction_pool.ready();
+
+		for pending in pending_iterator {
+			if (self.now)() > deadline {
+				debug!("Consensus deadline reached when pushing block transactions, proceeding with proposing.");
+				break;
+			}
+
+			match block_builder.push_extrinsic(pending.data.clone()) {
+				Ok(()) => {
+					debug!("[{:?}] Pushed to the block.", pending.hash);
+					included += 1;
+				}
+				Err(error::Error(error::ErrorKind::ApplyExtrinsicFailed(ApplyError::FullBlock), _)) => {
+					if is_first {
+						debug!("[{:?}] Invalid transaction: FullBlock on empty block", pending.hash);
+						unqueue_invalid.push(pending.hash.clone());
+					} else if skipped < MAX_SKIPPED_TRANSACTIONS {
+						skipped += 1;
+						debug!(
+							"Block seems full, but will try {} more transactions before quitting.",
+							MAX_SKIPPED_TRANSACTIONS - skipped
+						);
+					} else {
+						debug!("Block is full, proceed with proposing.");
+						break;
+					}
+				}
+				Err(e) => {
+					debug!("[{:?}] Invalid transaction: {}", pending.hash, e);
+					unqueue_invalid.push(pending.hash.clone());
+				}
+			}
+
+			is_first = false;
+		}
+
+		debug!(
+			"Transactions: {} included, {} skipped on full block, {} culled as invalid.",
+			included,
+			skipped,
+			unqueue_invalid.len()
+		);
+
+		self.transaction_pool.remove_invalid(&unqueue_invalid);
+		ProposalStats { included, skipped, culled: unqueue_invalid.len(), ..Default::default() }
 	}
 }
 
@@ -278,19 +316,48 @@ mod tests {
 	use codec::Encode;
 	use std::cell::RefCell;
 	use consensus_common::{Environment, Proposer};
-	use test_client::{self, runtime::{Extrinsic, Transfer}, AccountKeyring};
+	use test_client::{self, runtime::{Block, Extrinsic, Transfer}, AccountKeyring};
 
 	fn extrinsic(nonce: u64) -> Extrinsic {
+		transfer(AccountKeyring::Alice, nonce, Default::default())
+	}
+
+	fn transfer(from: AccountKeyring, nonce: u64, amount: u64) -> Extrinsic {
 		let tx = Transfer {
-			amount: Default::default(),
+			amount,
 			nonce,
-			from: AccountKeyring::Alice.into(),
+			from: from.into(),
 			to: Default::default(),
 		};
-		let signature = AccountKeyring::from_public(&tx.from).unwrap().sign(&tx.encode()).into();
+		let signature = from.sign(&tx.encode()).into();
 		Extrinsic::Transfer(tx, signature)
 	}
 
+	/// A block builder treating transfers of at least `oversized` as too large to fit
+	/// and rejecting transfers of exactly `invalid`.
+	struct TestBlockBuilder {
+		oversized: u64,
+		invalid: u64,
+		pushed: Vec<Extrinsic>,
+	}
+
+	impl BlockBuilder<Block> for TestBlockBuilder {
+		fn push_extrinsic(&mut self, extrinsic: Extrinsic) -> Result<(), error::Error> {
+			let amount = match extrinsic {
+				Extrinsic::Transfer(ref tx, _) => tx.amount,
+				_ => 0,
+			};
+			if amount >= self.oversized {
+				Err(error::ErrorKind::ApplyExtrinsicFailed(ApplyError::FullBlock).into())
+			} else if amount == self.invalid {
+				Err(error::ErrorKind::ApplyExtrinsicFailed(ApplyError::CantPay).into())
+			} else {
+				self.pushed.push(extrinsic);
+				Ok(())
+			}
+		}
+	}
+
 	#[test]
 	fn should_cease_building_block_when_deadline_is_reached() {
 		// given
@@ -325,4 +392,110 @@ mod tests {
 		assert_eq!(txpool.ready().count(), 2);
 	}
 
+	#[test]
+	fn should_tally_included_and_culled_transactions() {
+		// given
+		let client = Arc::new(test_client::new());
+		let chain_api = transaction_pool::ChainApi::new(client.clone());
+		let txpool = Arc::new(TransactionPool::new(Default::default(), chain_api));
+
+		txpool.submit_at(&BlockId::number(0), vec![
+			transfer(AccountKeyring::Alice, 0, 10),
+			transfer(AccountKeyring::Alice, 1, 10),
+			// Bob can't pay for this one.
+			transfer(AccountKeyring::Bob, 0, 2000),
+		]).unwrap();
+
+		let proposer_factory = ProposerFactory {
+			client: client.clone(),
+			transaction_pool: txpool.clone(),
+		};
+
+		let proposer = proposer_factory.init(
+			&client.header(&BlockId::number(0)).unwrap().unwrap(),
+			&[]
+		).unwrap();
+
+		// when
+		let started = time::Instant::now();
+		let deadline = started + time::Duration::from_secs(60);
+		let (block, stats) = proposer.propose_with(Default::default(), started, deadline).unwrap();
+
+		// then
+		assert_eq!(block.extrinsics().len(), 2);
+		assert_eq!(stats, ProposalStats { included: 2, skipped: 0, culled: 1, elapsed: stats.elapsed });
+	}
+
+	#[test]
+	fn should_report_block_assembly_time() {
+		// given
+		let client = Arc::new(test_client::new());
+		let chain_api = transaction_pool::ChainApi::new(client.clone());
+		let txpool = Arc::new(TransactionPool::new(Default::default(), chain_api));
+
+		txpool.submit_at(&BlockId::number(0), vec![extrinsic(0)]).unwrap();
+
+		let proposer_factory = ProposerFactory {
+			client: client.clone(),
+			transaction_pool: txpool.clone(),
+		};
+
+		let mut proposer = proposer_factory.init(
+			&client.header(&BlockId::number(0)).unwrap().unwrap(),
+			&[]
+		).unwrap();
+
+		// when
+		// every reading of the clock advances it by a second.
+		let started = time::Instant::now();
+		let cell = RefCell::new(started);
+		proposer.now = Box::new(move || {
+			let new = *cell.borrow() + time::Duration::from_secs(1);
+			cell.replace(new);
+			new
+		});
+		let deadline = started + time::Duration::from_secs(60);
+		let (block, stats) = proposer.propose_with(Default::default(), started, deadline).unwrap();
+
+		// then
+		// the clock was read once before pushing the transaction and once after building the block.
+		assert_eq!(block.extrinsics().len(), 1);
+		assert_eq!(stats.elapsed, time::Duration::from_secs(2));
+	}
+
+	#[test]
+	fn should_tally_oversized_transactions() {
+		// given
+		let client = Arc::new(test_client::new());
+		let chain_api = transaction_pool::ChainApi::new(client.clone());
+		let txpool = Arc::new(TransactionPool::new(Default::default(), chain_api));
+
+		txpool.submit_at(&BlockId::number(0), vec![
+			// too large even for an empty block.
+			transfer(AccountKeyring::Alice, 0, 1000),
+			transfer(AccountKeyring::Alice, 1, 10),
+			// doesn't fit anymore.
+			transfer(AccountKeyring::Alice, 2, 1000),
+			transfer(AccountKeyring::Alice, 3, 13),
+		]).unwrap();
+
+		let proposer_factory = ProposerFactory {
+			client: client.clone(),
+			transaction_pool: txpool.clone(),
+		};
+
+		let proposer = proposer_factory.init(
+			&client.header(&BlockId::number(0)).unwrap().unwrap(),
+			&[]
+		).unwrap();
+
+		// when
+		let mut block_builder = TestBlockBuilder { oversized: 1000, invalid: 13, pushed: Vec::new() };
+		let deadline = time::Instant::now() + time::Duration::from_secs(60);
+		let stats = proposer.push_ready(&mut block_builder, deadline);
+
+		// then
+		assert_eq!(block_builder.pushed.len(), 1);
+		assert_eq!(stats, ProposalStats { included: 1, skipped: 1, culled: 2, ..Default::default() });
+	}
 }
@@ -68,4 +68,5 @@ pub use core::result;
 
 pub mod collections {
 	pub use alloc::collections::btree_map;
+	pub use alloc::collections::btree_set;
 }
@@ -156,6 +156,8 @@ fn staging_testnet_config_genesis() -> GenesisConfig {
 			max_depth: 1024,
 			block_gas_limit: 10_000_000,
 			current_schedule: Default::default(),
+			emit_storage_events: false,
+			max_staged_bytes: 16 * 1024 * 1024,
 		}),
 		sudo: Some(SudoConfig {
 			key: endowed_accounts[0].clone(),
@@ -312,6 +314,8 @@ pub fn testnet_genesis(
 			max_depth: 1024,
 			block_gas_limit: 10_000_000,
 			current_schedule: Default::default(),
+			emit_storage_events: false,
+			max_staged_bytes: 16 * 1024 * 1024,
 		}),
 		sudo: Some(SudoConfig {
 			key: root_key,
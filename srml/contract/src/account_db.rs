@@ -16,11 +16,25 @@
 
 //! Auxilliaries to help with managing partial changes to accounts state.
 
-use super::{CodeHash, CodeHashOf, StorageOf, Trait};
+use super::{CodeHash, CodeHashOf, CodeStorage, Module, RawEvent, StorageOf, Trait};
 use {balances, system};
 use rstd::cell::RefCell;
 use rstd::collections::btree_map::{BTreeMap, Entry};
+#[cfg(test)]
+use rstd::collections::btree_set::BTreeSet;
+#[cfg(not(test))]
+use rstd::marker::PhantomData;
+use rstd::mem;
+#[cfg(test)]
+use rstd::ops::{Deref, DerefMut};
 use rstd::prelude::*;
+#[cfg(test)]
+use parity_codec::Codec;
+use parity_codec::{Decode, Encode, Input, Output};
+#[cfg(test)]
+use runtime_primitives::traits::Hash;
+#[cfg(any(test, feature = "integrity-checks"))]
+use runtime_io::blake2_256;
 use srml_support::{StorageMap, StorageDoubleMap, traits::UpdateBalanceOutcome};
 
 pub struct ChangeEntry<T: Trait> {
@@ -41,40 +55,277 @@ impl<T: Trait> Default for ChangeEntry<T> {
 	}
 }
 
+// Cannot derive(Clone) for the same reason.
+impl<T: Trait> Clone for ChangeEntry<T> {
+	fn clone(&self) -> Self {
+		ChangeEntry {
+			balance: self.balance.clone(),
+			code: self.code.clone(),
+			storage: self.storage.clone(),
+		}
+	}
+}
+
+impl<T: Trait> ChangeEntry<T> {
+	/// Returns `true` if this entry doesn't carry any change.
+	pub fn is_empty(&self) -> bool {
+		self.balance.is_none() && self.code.is_none() && self.storage.is_empty()
+	}
+}
+
+// Storage is encoded as a list of `(location, value)` pairs in key order, so the encoding
+// is deterministic. A `None` value marks a removal.
+impl<T: Trait> Encode for ChangeEntry<T> {
+	fn encode_to<W: Output>(&self, dest: &mut W) {
+		dest.push(&self.balance);
+		dest.push(&self.code);
+		dest.push(&self.storage.iter().collect::<Vec<_>>());
+	}
+}
+
+impl<T: Trait> Decode for ChangeEntry<T> {
+	fn decode<I: Input>(input: &mut I) -> Option<Self> {
+		Some(ChangeEntry {
+			balance: Decode::decode(input)?,
+			code: Decode::decode(input)?,
+			storage: Vec::<(Vec<u8>, Option<Vec<u8>>)>::decode(input)?.into_iter().collect(),
+		})
+	}
+}
+
 pub type ChangeSet<T> = BTreeMap<<T as system::Trait>::AccountId, ChangeEntry<T>>;
 
+/// Encode the change set as a list of `(account, change)` pairs ordered by account.
+///
+/// Nothing snapshots overlays outside the tests yet, so this and [`decode_change_set`] are
+/// only available there.
+///
+/// [`decode_change_set`]: fn.decode_change_set.html
+#[cfg(test)]
+pub fn encode_change_set<T: Trait>(s: &ChangeSet<T>) -> Vec<u8> {
+	s.iter().collect::<Vec<_>>().encode()
+}
+
+/// Decode a change set previously encoded with [`encode_change_set`].
+///
+/// [`encode_change_set`]: fn.encode_change_set.html
+#[cfg(test)]
+pub fn decode_change_set<T: Trait>(mut encoded: &[u8]) -> Option<ChangeSet<T>> {
+	Vec::<(T::AccountId, ChangeEntry<T>)>::decode(&mut encoded).map(|s| s.into_iter().collect())
+}
+
+/// An error returned when an account couldn't be restored.
+#[cfg(test)]
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(PartialEq, Eq)]
+pub enum RestoreError {
+	/// The supplied code hash and storage don't hash to the expected tombstone.
+	TombstoneMismatch,
+	/// The destination account has code, i.e. it is alive and was never purged.
+	AccountAlive,
+	/// The supplied code hash isn't in the code storage.
+	UnknownCodeHash,
+	/// Staging the account would exceed the overlay's `max_staged_bytes`.
+	StagingLimitExceeded,
+}
+
+/// An error returned when a change set can't be merged by [`OverlayAccountDb::commit_restricted`].
+///
+/// [`OverlayAccountDb::commit_restricted`]: struct.OverlayAccountDb.html#method.commit_restricted
+#[cfg(test)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestrictedCommitError<AccountId> {
+	/// The change set touches the given account, which it isn't allowed to.
+	ForbiddenAccount(AccountId),
+	/// Merging the change set would exceed the overlay's `max_staged_bytes`.
+	StagingLimitExceeded,
+}
+
+/// An error returned when staging a change would exceed the overlay's `max_staged_bytes`.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(PartialEq, Eq)]
+pub struct StagingLimitExceeded;
+
+/// Number of bytes a staged storage entry accounts for.
+fn staged_entry_len(location: &[u8], value: &Option<Vec<u8>>) -> usize {
+	location.len() + value.as_ref().map_or(0, |v| v.len())
+}
+
+/// Number of bytes a staged code change accounts for.
+fn staged_code_len<T: Trait>() -> usize {
+	mem::size_of::<CodeHash<T>>()
+}
+
+/// Compute the tombstone hash of an account given its code hash and full storage.
+///
+/// The storage is hashed in the key order of the map, so the result doesn't depend
+/// on the order in which the entries were inserted.
+#[cfg(test)]
+pub fn tombstone_hash<T: Trait>(code_hash: &CodeHash<T>, storage: &BTreeMap<Vec<u8>, Vec<u8>>) -> T::Hash {
+	let mut buf = code_hash.encode();
+	for (k, v) in storage.iter() {
+		k.encode_to(&mut buf);
+		v.encode_to(&mut buf);
+	}
+	T::Hashing::hash(&buf)
+}
+
 pub trait AccountDb<T: Trait> {
 	fn get_storage(&self, account: &T::AccountId, location: &[u8]) -> Option<Vec<u8>>;
 	fn get_code(&self, account: &T::AccountId) -> Option<CodeHash<T>>;
 	fn get_balance(&self, account: &T::AccountId) -> T::Balance;
+	/// Returns the free and the reserved balance of the given account.
+	fn get_balances(&self, account: &T::AccountId) -> (T::Balance, T::Balance);
+
+	/// Read and decode the value at `location`, returning `fallback` if the slot is empty or its
+	/// contents can't be decoded as `V`.
+	fn get_storage_or<V: Decode>(&self, account: &T::AccountId, location: &[u8], fallback: V) -> V
+	where
+		Self: Sized,
+	{
+		self.get_storage(account, location)
+			.and_then(|value| V::decode(&mut &value[..]))
+			.unwrap_or(fallback)
+	}
+
+	/// Same as [`get_storage_or`], but falls back to `V::default()`.
+	///
+	/// [`get_storage_or`]: #method.get_storage_or
+	fn get_storage_or_default<V: Decode + Default>(&self, account: &T::AccountId, location: &[u8]) -> V
+	where
+		Self: Sized,
+	{
+		self.get_storage_or(account, location, V::default())
+	}
 
 	fn commit(&mut self, change_set: ChangeSet<T>);
 }
 
-pub struct DirectAccountDb;
-impl<T: Trait> AccountDb<T> for DirectAccountDb {
-	fn get_storage(&self, account: &T::AccountId, location: &[u8]) -> Option<Vec<u8>> {
-		<StorageOf<T>>::get(account, &location.to_vec())
+/// An error returned when a change set is rejected as a whole.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(PartialEq, Eq)]
+pub enum CommitError {
+	/// An account's code was set to a code hash which isn't in the code storage.
+	UnknownCodeHash,
+	/// An account's balance would drop below the existential deposit, killing it.
+	#[cfg(test)]
+	WouldKillAccount,
+	/// An account which doesn't exist yet would be created.
+	#[cfg(test)]
+	WouldCreateAccount,
+	/// Merging the change set would exceed the overlay's `max_staged_bytes`.
+	#[cfg(test)]
+	StagingLimitExceeded,
+}
+
+/// The outcome of a successfully applied change set.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(PartialEq, Eq)]
+pub struct CommitSummary<AccountId> {
+	/// Accounts that were killed as a result of their balance update.
+	///
+	/// This is every account whose new free balance ended up below the existential deposit,
+	/// not only those explicitly set to zero. Their code and storage are removed by
+	/// `OnFreeBalanceZero` and any storage changes staged for them are not applied.
+	///
+	/// The runtime has no bookkeeping to do for them yet, so they are only collected in tests.
+	#[cfg(test)]
+	pub killed: Vec<AccountId>,
+	#[cfg(not(test))]
+	_marker: PhantomData<AccountId>,
+}
+
+/// Check that every account in the change set can be committed.
+fn validate_change_set<T: Trait>(s: &ChangeSet<T>) -> Result<(), CommitError> {
+	for changed in s.values() {
+		if let Some(Some(ref code_hash)) = changed.code {
+			if !<CodeStorage<T>>::exists(code_hash) {
+				return Err(CommitError::UnknownCodeHash);
+			}
+		}
 	}
-	fn get_code(&self, account: &T::AccountId) -> Option<CodeHash<T>> {
-		<CodeHashOf<T>>::get(account)
+	Ok(())
+}
+
+pub struct DirectAccountDb;
+impl DirectAccountDb {
+	/// Commit the given change set atomically.
+	///
+	/// The whole change set is validated before anything is written, so either all accounts
+	/// are updated or, if any of them is invalid, none are.
+	pub fn try_commit<T: Trait>(
+		&mut self,
+		s: ChangeSet<T>,
+	) -> Result<CommitSummary<T::AccountId>, CommitError> {
+		validate_change_set(&s)?;
+		Ok(Self::apply(s, None))
 	}
-	fn get_balance(&self, account: &T::AccountId) -> T::Balance {
-		balances::Module::<T>::free_balance(account)
+
+	/// Commit the given change set, recording the previous values of everything it overwrites.
+	///
+	/// The returned journal can be used to revert the commit. Killing an account removes its code,
+	/// storage and more via `OnFreeBalanceZero`, and creating one calls `OnNewAccount` and deposits
+	/// `NewAccount`. Neither can be journaled, so change sets that would drop any balance below the
+	/// existential deposit or set the balance of an account that doesn't exist yet are rejected
+	/// before anything is written.
+	///
+	/// Nothing in the runtime needs to revert a commit yet, so this is only available in tests.
+	#[cfg(test)]
+	pub fn commit_with_journal<T: Trait>(
+		&mut self,
+		s: ChangeSet<T>,
+	) -> Result<CommitJournal<T>, CommitError> {
+		validate_change_set(&s)?;
+		let existential_deposit = balances::Module::<T>::existential_deposit();
+		for (address, changed) in s.iter() {
+			if let Some(balance) = changed.balance {
+				if balance < existential_deposit {
+					return Err(CommitError::WouldKillAccount);
+				}
+				if !<balances::FreeBalance<T>>::exists(address) {
+					return Err(CommitError::WouldCreateAccount);
+				}
+			}
+		}
+
+		let mut journal = CommitJournal { entries: Vec::new() };
+		Self::apply(s, Some(&mut journal.entries));
+		Ok(journal)
 	}
-	fn commit(&mut self, s: ChangeSet<T>) {
+
+	fn apply<T: Trait>(
+		s: ChangeSet<T>,
+		mut journal: Option<&mut Vec<JournalEntry<T>>>,
+	) -> CommitSummary<T::AccountId> {
+		let emit_storage_events = <Module<T>>::emit_storage_events();
+		#[cfg(test)]
+		let mut killed = Vec::new();
 		for (address, changed) in s.into_iter() {
 			if let Some(balance) = changed.balance {
+				if let Some(journal) = journal.as_mut() {
+					journal.push(JournalEntry::Balance(
+						address.clone(),
+						balances::Module::<T>::free_balance(&address),
+					));
+				}
+				// NOTE: if the account didn't exist before, `set_free_balance_creating` registers it
+				// and calls `OnNewAccount`, so the lifecycle hooks fire the same way as for transfers.
 				if let UpdateBalanceOutcome::AccountKilled =
 					balances::Module::<T>::set_free_balance_creating(&address, balance)
 				{
 					// Account killed. This will ultimately lead to calling `OnFreeBalanceZero` callback
 					// which will make removal of CodeHashOf and StorageOf for this account.
 					// In order to avoid writing over the deleted properties we `continue` here.
+					// NOTE: the removed storage is not reported through `StorageChanged` events.
+					#[cfg(test)]
+					killed.push(address);
 					continue;
 				}
 			}
 			if let Some(code) = changed.code {
+				if let Some(journal) = journal.as_mut() {
+					journal.push(JournalEntry::Code(address.clone(), <CodeHashOf<T>>::get(&address)));
+				}
 				if let Some(code) = code {
 					<CodeHashOf<T>>::insert(&address, code);
 				} else {
@@ -82,6 +333,13 @@ impl<T: Trait> AccountDb<T> for DirectAccountDb {
 				}
 			}
 			for (k, v) in changed.storage.into_iter() {
+				if let Some(journal) = journal.as_mut() {
+					let previous = <StorageOf<T>>::get(&address, &k);
+					journal.push(JournalEntry::Storage(address.clone(), k.clone(), previous));
+				}
+				if emit_storage_events {
+					<Module<T>>::deposit_event(RawEvent::StorageChanged(address.clone(), k.clone(), v.clone()));
+				}
 				if let Some(value) = v {
 					<StorageOf<T>>::insert(&address, &k, value);
 				} else {
@@ -89,31 +347,203 @@ impl<T: Trait> AccountDb<T> for DirectAccountDb {
 				}
 			}
 		}
+		CommitSummary {
+			#[cfg(test)]
+			killed,
+			#[cfg(not(test))]
+			_marker: PhantomData,
+		}
+	}
+}
+
+enum JournalEntry<T: Trait> {
+	Balance(T::AccountId, T::Balance),
+	Code(T::AccountId, Option<CodeHash<T>>),
+	Storage(T::AccountId, Vec<u8>, Option<Vec<u8>>),
+}
+
+/// Previous values of everything overwritten by [`DirectAccountDb::commit_with_journal`].
+///
+/// [`DirectAccountDb::commit_with_journal`]: struct.DirectAccountDb.html#method.commit_with_journal
+#[cfg(test)]
+pub struct CommitJournal<T: Trait> {
+	entries: Vec<JournalEntry<T>>,
+}
+
+#[cfg(test)]
+impl<T: Trait> CommitJournal<T> {
+	/// Revert the journaled commit by writing back the previous values in reverse order.
+	///
+	/// If `EmitStorageEvents` is enabled, a `StorageChanged` event is deposited for every storage
+	/// entry written back, so the reverted values can be followed the same way as the commit.
+	pub fn undo(self) {
+		let emit_storage_events = <Module<T>>::emit_storage_events();
+		for entry in self.entries.into_iter().rev() {
+			match entry {
+				JournalEntry::Balance(address, balance) => {
+					balances::Module::<T>::set_free_balance(&address, balance);
+				}
+				JournalEntry::Code(address, Some(code)) => <CodeHashOf<T>>::insert(&address, code),
+				JournalEntry::Code(address, None) => <CodeHashOf<T>>::remove(&address),
+				JournalEntry::Storage(address, k, v) => {
+					if emit_storage_events {
+						<Module<T>>::deposit_event(RawEvent::StorageChanged(address.clone(), k.clone(), v.clone()));
+					}
+					if let Some(value) = v {
+						<StorageOf<T>>::insert(&address, &k, value);
+					} else {
+						<StorageOf<T>>::remove(&address, &k);
+					}
+				}
+			}
+		}
+	}
+}
+impl<T: Trait> AccountDb<T> for DirectAccountDb {
+	fn get_storage(&self, account: &T::AccountId, location: &[u8]) -> Option<Vec<u8>> {
+		<StorageOf<T>>::get(account, &location.to_vec())
+	}
+	fn get_code(&self, account: &T::AccountId) -> Option<CodeHash<T>> {
+		<CodeHashOf<T>>::get(account)
+	}
+	fn get_balance(&self, account: &T::AccountId) -> T::Balance {
+		balances::Module::<T>::free_balance(account)
+	}
+	fn get_balances(&self, account: &T::AccountId) -> (T::Balance, T::Balance) {
+		(
+			balances::Module::<T>::free_balance(account),
+			balances::Module::<T>::reserved_balance(account),
+		)
+	}
+	fn commit(&mut self, s: ChangeSet<T>) {
+		Self::apply(s, None);
+	}
+}
+
+/// A running checksum of the storage staged in an overlay.
+///
+/// This is the xor of the hashes of every staged `(account, location, value)`, so the
+/// contribution of an entry can be removed by toggling it once more.
+#[cfg(any(test, feature = "integrity-checks"))]
+#[derive(Default, PartialEq, Eq)]
+struct StorageChecksum([u8; 32]);
+
+#[cfg(any(test, feature = "integrity-checks"))]
+impl StorageChecksum {
+	fn toggle<T: Trait>(&mut self, account: &T::AccountId, location: &[u8], value: &Option<Vec<u8>>) {
+		let mut buf = account.encode();
+		location.encode_to(&mut buf);
+		value.encode_to(&mut buf);
+		for (acc, byte) in self.0.iter_mut().zip(blake2_256(&buf).iter()) {
+			*acc ^= byte;
+		}
 	}
 }
 
 pub struct OverlayAccountDb<'a, T: Trait + 'a> {
 	local: RefCell<ChangeSet<T>>,
 	underlying: &'a AccountDb<T>,
+	/// Total size of the staged storage keys, values and code hashes.
+	staged_bytes: usize,
+	max_staged_bytes: Option<usize>,
+	#[cfg(any(test, feature = "integrity-checks"))]
+	checksum: StorageChecksum,
 }
 impl<'a, T: Trait> OverlayAccountDb<'a, T> {
 	pub fn new(underlying: &'a AccountDb<T>) -> OverlayAccountDb<'a, T> {
 		OverlayAccountDb {
 			local: RefCell::new(ChangeSet::new()),
 			underlying,
+			staged_bytes: 0,
+			max_staged_bytes: None,
+			#[cfg(any(test, feature = "integrity-checks"))]
+			checksum: Default::default(),
 		}
 	}
 
-	pub fn into_change_set(self) -> ChangeSet<T> {
-		self.local.into_inner()
+	/// Create an overlay with `change_set` already staged on top of `underlying`.
+	///
+	/// Together with [`decode_change_set`] this allows to resume from a snapshot of another
+	/// overlay's changes.
+	///
+	/// [`decode_change_set`]: fn.decode_change_set.html
+	#[cfg(test)]
+	pub fn from_change_set(underlying: &'a AccountDb<T>, change_set: ChangeSet<T>) -> OverlayAccountDb<'a, T> {
+		let mut overlay = OverlayAccountDb::new(underlying);
+		overlay.commit(change_set);
+		overlay
 	}
 
-	pub fn set_storage(
-		&mut self,
-		account: &T::AccountId,
-		location: Vec<u8>,
-		value: Option<Vec<u8>>,
-	) {
+	/// Limit the total size of storage keys, values and code hashes this overlay may stage.
+	/// The overlay is unbounded by default.
+	///
+	/// Once the limit would be exceeded [`set_storage`], [`set_code`] and [`merge`] fail.
+	///
+	/// [`set_storage`]: #method.set_storage
+	/// [`set_code`]: #method.set_code
+	/// [`merge`]: #method.merge
+	pub fn with_max_staged_bytes(mut self, max_staged_bytes: usize) -> Self {
+		self.max_staged_bytes = Some(max_staged_bytes);
+		self
+	}
+
+	/// Create an overlay for a nested frame on top of this one.
+	///
+	/// The nested overlay has the same `max_staged_bytes`. What it stages never exceeds what
+	/// this overlay stages once it is merged, so it only fails early where [`merge`] would
+	/// fail anyway. Entries it replaces are only known when merging, so that's where the
+	/// combined size is checked.
+	///
+	/// [`merge`]: #method.merge
+	pub fn nested<'s>(&'s self) -> OverlayAccountDb<'s, T> {
+		let mut nested = OverlayAccountDb::new(self);
+		nested.max_staged_bytes = self.max_staged_bytes;
+		nested
+	}
+
+	/// Total size of the storage keys, values and code hashes currently staged.
+	#[cfg(test)]
+	pub fn staged_bytes(&self) -> usize {
+		self.staged_bytes
+	}
+
+	/// Compute the staged size this overlay would have after merging `s`.
+	fn staged_bytes_after(&self, s: &ChangeSet<T>) -> usize {
+		let local = self.local.borrow();
+		let mut staged_bytes = self.staged_bytes;
+		for (account, changed) in s.iter() {
+			let current = local.get(account);
+			if changed.code.is_some() && current.map_or(true, |c| c.code.is_none()) {
+				staged_bytes += staged_code_len::<T>();
+			}
+			for (location, value) in changed.storage.iter() {
+				let replaced = current
+					.and_then(|c| c.storage.get(location))
+					.map_or(0, |old| staged_entry_len(location, old));
+				staged_bytes = staged_bytes - replaced + staged_entry_len(location, value);
+			}
+		}
+		staged_bytes
+	}
+
+	/// Check that the running checksum matches the storage currently staged.
+	///
+	/// This is a diagnostic for tests and fuzzing, it is only available with the
+	/// `integrity-checks` feature.
+	#[cfg(any(test, feature = "integrity-checks"))]
+	pub fn verify_integrity(&self) -> bool {
+		let mut expected = StorageChecksum::default();
+		for (account, changed) in self.local.borrow().iter() {
+			for (location, value) in changed.storage.iter() {
+				expected.toggle::<T>(account, location, value);
+			}
+		}
+		expected == self.checksum
+	}
+
+	/// Stage a storage value bypassing the checksum bookkeeping.
+	#[cfg(test)]
+	pub fn tamper_storage(&mut self, account: &T::AccountId, location: Vec<u8>, value: Option<Vec<u8>>) {
 		self.local
 			.borrow_mut()
 			.entry(account.clone())
@@ -121,12 +551,172 @@ impl<'a, T: Trait> OverlayAccountDb<'a, T> {
 			.storage
 			.insert(location, value);
 	}
-	pub fn set_code(&mut self, account: &T::AccountId, code: Option<CodeHash<T>>) {
+
+	/// Validate the change set and merge it into this overlay.
+	///
+	/// Unlike [`commit`], which is meant for trusted merges, this rejects invalid change sets
+	/// at the overlay boundary instead of when they reach the storage, as well as change sets
+	/// that would exceed `max_staged_bytes`.
+	///
+	/// Contract execution merges nested frames with [`merge`] instead, so this is only available
+	/// in tests.
+	///
+	/// [`commit`]: trait.AccountDb.html#tymethod.commit
+	/// [`merge`]: #method.merge
+	#[cfg(test)]
+	pub fn try_commit(&mut self, s: ChangeSet<T>) -> Result<(), CommitError> {
+		validate_change_set(&s)?;
+		self.merge(s).map_err(|_| CommitError::StagingLimitExceeded)
+	}
+
+	/// Merge the changes of a nested frame into this overlay.
+	///
+	/// Unlike [`commit`], this respects `max_staged_bytes`: if merging would exceed it, the whole
+	/// change set is rejected and nothing is merged.
+	///
+	/// [`commit`]: trait.AccountDb.html#tymethod.commit
+	pub fn merge(&mut self, s: ChangeSet<T>) -> Result<(), StagingLimitExceeded> {
+		if let Some(max) = self.max_staged_bytes {
+			if self.staged_bytes_after(&s) > max {
+				return Err(StagingLimitExceeded);
+			}
+		}
+		self.commit(s);
+		Ok(())
+	}
+
+	/// Merge the change set into this overlay, provided it only touches the `allowed` accounts.
+	///
+	/// If any other account is touched, or merging would exceed `max_staged_bytes`, the whole
+	/// change set is rejected and nothing is merged.
+	///
+	/// Contract execution doesn't restrict its frames yet, so this is only available in tests.
+	#[cfg(test)]
+	pub fn commit_restricted(
+		&mut self,
+		s: ChangeSet<T>,
+		allowed: &BTreeSet<T::AccountId>,
+	) -> Result<(), RestrictedCommitError<T::AccountId>> {
+		if let Some(account) = s.keys().find(|account| !allowed.contains(*account)).cloned() {
+			return Err(RestrictedCommitError::ForbiddenAccount(account));
+		}
+		self.merge(s).map_err(|_| RestrictedCommitError::StagingLimitExceeded)
+	}
+
+	/// Consume the overlay and return the staged changes.
+	///
+	/// Accounts which were touched but don't end up with any effective change are
+	/// left out, so committing the result doesn't do any pointless writes.
+	pub fn into_change_set(self) -> ChangeSet<T> {
 		self.local
-			.borrow_mut()
-			.entry(account.clone())
-			.or_insert(Default::default())
-			.code = Some(code);
+			.into_inner()
+			.into_iter()
+			.filter(|(_, changed)| !changed.is_empty())
+			.collect()
+	}
+
+	/// Stage a storage write, failing without staging anything if it would exceed
+	/// `max_staged_bytes`.
+	pub fn set_storage(
+		&mut self,
+		account: &T::AccountId,
+		location: Vec<u8>,
+		value: Option<Vec<u8>>,
+	) -> Result<(), StagingLimitExceeded> {
+		self.stage_storage(account, location, value, true)
+	}
+	fn stage_storage(
+		&mut self,
+		account: &T::AccountId,
+		location: Vec<u8>,
+		value: Option<Vec<u8>>,
+		enforce_limit: bool,
+	) -> Result<(), StagingLimitExceeded> {
+		let mut local = self.local.borrow_mut();
+		let changed = local.entry(account.clone()).or_insert(Default::default());
+		let replaced = changed.storage.get(&location);
+		let staged_bytes = self.staged_bytes
+			- replaced.map_or(0, |old| staged_entry_len(&location, old))
+			+ staged_entry_len(&location, &value);
+		if enforce_limit && self.max_staged_bytes.map_or(false, |max| staged_bytes > max) {
+			return Err(StagingLimitExceeded);
+		}
+		self.staged_bytes = staged_bytes;
+		// Only the replaced entry and the new one change the checksum.
+		#[cfg(any(test, feature = "integrity-checks"))]
+		{
+			if let Some(replaced) = replaced {
+				self.checksum.toggle::<T>(account, &location, replaced);
+			}
+			self.checksum.toggle::<T>(account, &location, &value);
+		}
+		changed.storage.insert(location, value);
+		Ok(())
+	}
+	/// Stage a code change, failing without staging anything if it would exceed
+	/// `max_staged_bytes`.
+	pub fn set_code(&mut self, account: &T::AccountId, code: Option<CodeHash<T>>) -> Result<(), StagingLimitExceeded> {
+		self.stage_code(account, code, true)
+	}
+	fn stage_code(
+		&mut self,
+		account: &T::AccountId,
+		code: Option<CodeHash<T>>,
+		enforce_limit: bool,
+	) -> Result<(), StagingLimitExceeded> {
+		let mut local = self.local.borrow_mut();
+		let changed = local.entry(account.clone()).or_insert(Default::default());
+		if changed.code.is_none() {
+			let staged_bytes = self.staged_bytes + staged_code_len::<T>();
+			if enforce_limit && self.max_staged_bytes.map_or(false, |max| staged_bytes > max) {
+				return Err(StagingLimitExceeded);
+			}
+			self.staged_bytes = staged_bytes;
+		}
+		changed.code = Some(code);
+		Ok(())
+	}
+	/// Restore a previously purged account by staging its code hash and storage.
+	///
+	/// `dest` must not have any code, `code_hash` must be in the code storage and together
+	/// with `storage` it must hash to `expected_hash` (see [`tombstone_hash`]). Otherwise
+	/// nothing is staged and an error is returned.
+	///
+	/// Contracts can't be purged in this tree, so there is nothing to restore yet and this is
+	/// only available in tests.
+	///
+	/// [`tombstone_hash`]: fn.tombstone_hash.html
+	#[cfg(test)]
+	pub fn restore(
+		&mut self,
+		dest: &T::AccountId,
+		code_hash: CodeHash<T>,
+		storage: BTreeMap<Vec<u8>, Vec<u8>>,
+		expected_hash: T::Hash,
+	) -> Result<(), RestoreError> {
+		if self.get_code(dest).is_some() {
+			return Err(RestoreError::AccountAlive);
+		}
+		if !<CodeStorage<T>>::exists(&code_hash) {
+			return Err(RestoreError::UnknownCodeHash);
+		}
+		if tombstone_hash::<T>(&code_hash, &storage) != expected_hash {
+			return Err(RestoreError::TombstoneMismatch);
+		}
+		// An upper bound, entries already staged for `dest` are replaced rather than added.
+		let required = staged_code_len::<T>()
+			+ storage.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+		if self.max_staged_bytes.map_or(false, |max| self.staged_bytes + required > max) {
+			return Err(RestoreError::StagingLimitExceeded);
+		}
+
+		// The limit was checked above for the account as a whole.
+		let _ = self.stage_code(dest, Some(code_hash), false);
+		for (location, value) in storage {
+			let _ = self.stage_storage(dest, location, Some(value), false);
+		}
+
+		Ok(())
 	}
 	pub fn set_balance(&mut self, account: &T::AccountId, balance: T::Balance) {
 		self.local
@@ -135,6 +725,71 @@ impl<'a, T: Trait> OverlayAccountDb<'a, T> {
 			.or_insert(Default::default())
 			.balance = Some(balance);
 	}
+
+	/// Get a mutable handle to the decoded value at `location`.
+	///
+	/// The value is decoded now (falling back to `V::default()`) and staged back when
+	/// the handle is dropped, but only if it was actually changed. Writing back can't fail,
+	/// so it isn't subject to `max_staged_bytes`.
+	///
+	/// Nothing outside the tests edits structured values yet, so this is only available there.
+	#[cfg(test)]
+	pub fn staged<'o, V: Codec + Default>(
+		&'o mut self,
+		account: &T::AccountId,
+		location: Vec<u8>,
+	) -> StagedValue<'o, 'a, T, V> {
+		let value: V = self.get_storage_or_default(account, &location);
+		StagedValue {
+			original: value.encode(),
+			overlay: self,
+			account: account.clone(),
+			location,
+			value,
+		}
+	}
+}
+
+/// A decoded storage value, staged back into the overlay when dropped.
+///
+/// See [`OverlayAccountDb::staged`].
+///
+/// [`OverlayAccountDb::staged`]: struct.OverlayAccountDb.html#method.staged
+#[cfg(test)]
+pub struct StagedValue<'o, 'a: 'o, T: Trait + 'a, V: Encode> {
+	overlay: &'o mut OverlayAccountDb<'a, T>,
+	account: T::AccountId,
+	location: Vec<u8>,
+	original: Vec<u8>,
+	value: V,
+}
+
+#[cfg(test)]
+impl<'o, 'a: 'o, T: Trait + 'a, V: Encode> Deref for StagedValue<'o, 'a, T, V> {
+	type Target = V;
+
+	fn deref(&self) -> &V {
+		&self.value
+	}
+}
+
+#[cfg(test)]
+impl<'o, 'a: 'o, T: Trait + 'a, V: Encode> DerefMut for StagedValue<'o, 'a, T, V> {
+	fn deref_mut(&mut self) -> &mut V {
+		&mut self.value
+	}
+}
+
+#[cfg(test)]
+impl<'o, 'a: 'o, T: Trait + 'a, V: Encode> Drop for StagedValue<'o, 'a, T, V> {
+	fn drop(&mut self) {
+		let encoded = self.value.encode();
+		if encoded != self.original {
+			let location = mem::replace(&mut self.location, Vec::new());
+			// Can't fail, the limit isn't enforced here.
+			let _ = self.overlay.stage_storage(&self.account, location, Some(encoded), false);
+		}
+	}
 }
 
 impl<'a, T: Trait> AccountDb<T> for OverlayAccountDb<'a, T> {
@@ -160,10 +815,23 @@ impl<'a, T: Trait> AccountDb<T> for OverlayAccountDb<'a, T> {
 			.and_then(|a| a.balance)
 			.unwrap_or_else(|| self.underlying.get_balance(account))
 	}
+	fn get_balances(&self, account: &T::AccountId) -> (T::Balance, T::Balance) {
+		// Only the free balance can be staged, the reserved one always comes from the underlying db.
+		let (free, reserved) = self.underlying.get_balances(account);
+		let free = self.local
+			.borrow()
+			.get(account)
+			.and_then(|a| a.balance)
+			.unwrap_or(free);
+		(free, reserved)
+	}
+	// NOTE: this is a trusted merge which doesn't enforce `max_staged_bytes`, see `merge`.
 	fn commit(&mut self, s: ChangeSet<T>) {
 		let mut local = self.local.borrow_mut();
 
 		for (address, changed) in s.into_iter() {
+			#[cfg(any(test, feature = "integrity-checks"))]
+			let account = address.clone();
 			match local.entry(address) {
 				Entry::Occupied(e) => {
 					let mut value = e.into_mut();
@@ -171,11 +839,35 @@ impl<'a, T: Trait> AccountDb<T> for OverlayAccountDb<'a, T> {
 						value.balance = changed.balance;
 					}
 					if changed.code.is_some() {
+						if value.code.is_none() {
+							self.staged_bytes += staged_code_len::<T>();
+						}
 						value.code = changed.code;
 					}
-					value.storage.extend(changed.storage.into_iter());
+					for (location, v) in changed.storage.into_iter() {
+						let replaced = value.storage.get(&location);
+						self.staged_bytes = self.staged_bytes
+							- replaced.map_or(0, |old| staged_entry_len(&location, old))
+							+ staged_entry_len(&location, &v);
+						#[cfg(any(test, feature = "integrity-checks"))]
+						{
+							if let Some(replaced) = replaced {
+								self.checksum.toggle::<T>(&account, &location, replaced);
+							}
+							self.checksum.toggle::<T>(&account, &location, &v);
+						}
+						value.storage.insert(location, v);
+					}
 				}
 				Entry::Vacant(e) => {
+					if changed.code.is_some() {
+						self.staged_bytes += staged_code_len::<T>();
+					}
+					for (location, v) in changed.storage.iter() {
+						self.staged_bytes += staged_entry_len(location, v);
+						#[cfg(any(test, feature = "integrity-checks"))]
+						self.checksum.toggle::<T>(&account, location, v);
+					}
 					e.insert(changed);
 				}
 			}
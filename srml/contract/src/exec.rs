@@ -29,6 +29,10 @@ pub type CallOf<T> = <T as Trait>::Call;
 pub type MomentOf<T> = <T as timestamp::Trait>::Moment;
 pub type SeedOf<T> = <T as system::Trait>::Hash;
 
+/// The error returned when a change can't be staged because the execution reached its
+/// staging limit.
+const STAGING_LIMIT_EXCEEDED: &'static str = "staged storage limit exceeded";
+
 #[cfg_attr(test, derive(Debug))]
 pub struct InstantiateReceipt<AccountId> {
 	pub address: AccountId,
@@ -56,8 +60,9 @@ pub trait Ext {
 
 	/// Sets the storage entry by the given key to the specified value.
 	///
-	/// If `value` is `None` then the storage entry is deleted.
-	fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>);
+	/// If `value` is `None` then the storage entry is deleted. Returns an error if the
+	/// change can't be staged because the execution reached its staging limit.
+	fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), &'static str>;
 
 	/// Instantiate a contract from the given code.
 	///
@@ -244,7 +249,8 @@ where
 	///
 	/// The specified `origin` address will be used as `sender` for
 	pub fn top_level(origin: T::AccountId, cfg: &'a Config<T>, vm: &'a V, loader: &'a L) -> Self {
-		let overlay = OverlayAccountDb::<T>::new(&DirectAccountDb);
+		let overlay = OverlayAccountDb::<T>::new(&DirectAccountDb)
+			.with_max_staged_bytes(cfg.max_staged_bytes);
 		ExecutionContext {
 			self_account: origin,
 			depth: 0,
@@ -295,7 +301,7 @@ where
 
 		let (change_set, events, calls) = {
 			let mut nested = self.nested(
-				OverlayAccountDb::new(&self.overlay),
+				self.overlay.nested(),
 				dest.clone()
 			);
 
@@ -333,7 +339,7 @@ where
 			(nested.overlay.into_change_set(), nested.events, nested.calls)
 		};
 
-		self.overlay.commit(change_set);
+		self.overlay.merge(change_set).map_err(|_| STAGING_LIMIT_EXCEEDED)?;
 		self.events.extend(events);
 		self.calls.extend(calls);
 
@@ -370,8 +376,8 @@ where
 		}
 
 		let (change_set, events, calls) = {
-			let mut overlay = OverlayAccountDb::new(&self.overlay);
-			overlay.set_code(&dest, Some(code_hash.clone()));
+			let mut overlay = self.overlay.nested();
+			overlay.set_code(&dest, Some(code_hash.clone())).map_err(|_| STAGING_LIMIT_EXCEEDED)?;
 			let mut nested = self.nested(overlay, dest.clone());
 
 			// Send funds unconditionally here. If the `endowment` is below existential_deposit
@@ -408,7 +414,7 @@ where
 			(nested.overlay.into_change_set(), nested.events, nested.calls)
 		};
 
-		self.overlay.commit(change_set);
+		self.overlay.merge(change_set).map_err(|_| STAGING_LIMIT_EXCEEDED)?;
 		self.events.extend(events);
 		self.calls.extend(calls);
 
@@ -557,10 +563,11 @@ where
 		self.ctx.overlay.get_storage(&self.ctx.self_account, key)
 	}
 
-	fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+	fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), &'static str> {
 		self.ctx
 			.overlay
 			.set_storage(&self.ctx.self_account, key.to_vec(), value)
+			.map_err(|_| STAGING_LIMIT_EXCEEDED)
 	}
 
 	fn instantiate(
@@ -631,7 +638,7 @@ where
 mod tests {
 	use super::{
 		ExecFeeToken, ExecutionContext, Ext, Loader, EmptyOutputBuf, TransferFeeKind, TransferFeeToken,
-		Vm, VmExecResult, InstantiateReceipt, RawEvent,
+		Vm, VmExecResult, InstantiateReceipt, RawEvent, STAGING_LIMIT_EXCEEDED,
 	};
 	use crate::account_db::AccountDb;
 	use crate::gas::GasMeter;
@@ -753,7 +760,7 @@ mod tests {
 		with_externalities(&mut ExtBuilder::default().build(), || {
 			let cfg = Config::preload();
 			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&BOB, Some(exec_ch));
+			ctx.overlay.set_code(&BOB, Some(exec_ch)).unwrap();
 
 			assert_matches!(
 				ctx.call(BOB, value, &mut gas_meter, &data, EmptyOutputBuf::new()),
@@ -988,7 +995,7 @@ mod tests {
 		with_externalities(&mut ExtBuilder::default().build(), || {
 			let cfg = Config::preload();
 			let mut ctx = ExecutionContext::top_level(origin, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&BOB, Some(return_ch));
+			ctx.overlay.set_code(&BOB, Some(return_ch)).unwrap();
 
 			let result = ctx.call(
 				dest,
@@ -1016,7 +1023,7 @@ mod tests {
 		with_externalities(&mut ExtBuilder::default().build(), || {
 			let cfg = Config::preload();
 			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&BOB, Some(input_data_ch));
+			ctx.overlay.set_code(&BOB, Some(input_data_ch)).unwrap();
 
 			let result = ctx.call(
 				BOB,
@@ -1075,7 +1082,7 @@ mod tests {
 		with_externalities(&mut ExtBuilder::default().build(), || {
 			let cfg = Config::preload();
 			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&BOB, Some(recurse_ch));
+			ctx.overlay.set_code(&BOB, Some(recurse_ch)).unwrap();
 
 			let result = ctx.call(
 				BOB,
@@ -1122,8 +1129,8 @@ mod tests {
 			let cfg = Config::preload();
 
 			let mut ctx = ExecutionContext::top_level(origin, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&dest, Some(bob_ch));
-			ctx.overlay.set_code(&CHARLIE, Some(charlie_ch));
+			ctx.overlay.set_code(&dest, Some(bob_ch)).unwrap();
+			ctx.overlay.set_code(&CHARLIE, Some(charlie_ch)).unwrap();
 
 			let result = ctx.call(
 				dest,
@@ -1165,8 +1172,8 @@ mod tests {
 		with_externalities(&mut ExtBuilder::default().build(), || {
 			let cfg = Config::preload();
 			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
-			ctx.overlay.set_code(&BOB, Some(bob_ch));
-			ctx.overlay.set_code(&CHARLIE, Some(charlie_ch));
+			ctx.overlay.set_code(&BOB, Some(bob_ch)).unwrap();
+			ctx.overlay.set_code(&CHARLIE, Some(charlie_ch)).unwrap();
 
 			let result = ctx.call(
 				BOB,
@@ -1273,7 +1280,7 @@ mod tests {
 				let cfg = Config::preload();
 				let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
 				ctx.overlay.set_balance(&ALICE, 1000);
-				ctx.overlay.set_code(&BOB, Some(creator_ch));
+				ctx.overlay.set_code(&BOB, Some(creator_ch)).unwrap();
 
 				assert_matches!(
 					ctx.call(BOB, 20, &mut GasMeter::<Test>::with_limit(1000, 1), &[], EmptyOutputBuf::new()),
@@ -1324,7 +1331,7 @@ mod tests {
 				let cfg = Config::preload();
 				let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
 				ctx.overlay.set_balance(&ALICE, 1000);
-				ctx.overlay.set_code(&BOB, Some(creator_ch));
+				ctx.overlay.set_code(&BOB, Some(creator_ch)).unwrap();
 
 				assert_matches!(
 					ctx.call(BOB, 20, &mut GasMeter::<Test>::with_limit(1000, 1), &[], EmptyOutputBuf::new()),
@@ -1339,4 +1346,59 @@ mod tests {
 			}
 		);
 	}
+
+	#[test]
+	fn set_storage_traps_beyond_staging_limit() {
+		let vm = MockVm::new();
+
+		let mut loader = MockLoader::empty();
+		let code = loader.insert(|ctx| {
+			assert_eq!(ctx.ext.set_storage(&[1; 32], Some(vec![0; 8])), Ok(()));
+			match ctx.ext.set_storage(&[2; 32], Some(vec![0; 8])) {
+				Ok(()) => VmExecResult::Ok,
+				Err(reason) => VmExecResult::Trap(reason),
+			}
+		});
+
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let mut cfg = Config::preload();
+			cfg.max_staged_bytes = 60;
+			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
+			ctx.overlay.set_code(&BOB, Some(code)).unwrap();
+
+			// The nested frame can't stage two 40 byte entries on its own.
+			assert_matches!(
+				ctx.call(BOB, 0, &mut GasMeter::<Test>::with_limit(10000, 1), &[], EmptyOutputBuf::new()),
+				Err(STAGING_LIMIT_EXCEEDED)
+			);
+			assert_eq!(ctx.overlay.get_storage(&BOB, &[1; 32]), None);
+			assert_eq!(ctx.overlay.staged_bytes(), 32);
+		});
+	}
+
+	#[test]
+	fn call_fails_if_merging_exceeds_staging_limit() {
+		let vm = MockVm::new();
+
+		let mut loader = MockLoader::empty();
+		let code = loader.insert(|ctx| {
+			assert_eq!(ctx.ext.set_storage(&[1; 32], Some(vec![0; 8])), Ok(()));
+			VmExecResult::Ok
+		});
+
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let mut cfg = Config::preload();
+			cfg.max_staged_bytes = 60;
+			let mut ctx = ExecutionContext::top_level(ALICE, &cfg, &vm, &loader);
+			ctx.overlay.set_code(&BOB, Some(code)).unwrap();
+
+			// The 40 byte entry fits into the nested frame, but not next to the staged code hash.
+			assert_matches!(
+				ctx.call(BOB, 0, &mut GasMeter::<Test>::with_limit(10000, 1), &[], EmptyOutputBuf::new()),
+				Err(STAGING_LIMIT_EXCEEDED)
+			);
+			assert_eq!(ctx.overlay.get_storage(&BOB, &[1; 32]), None);
+			assert_eq!(ctx.overlay.staged_bytes(), 32);
+		});
+	}
 }
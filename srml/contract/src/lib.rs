@@ -51,6 +51,9 @@
 //! [`Module::on_finalise`]: struct.Module.html#impl-OnFinalise
 
 #![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "benchmarks", feature(test))]
+
+#[cfg(feature = "benchmarks")] extern crate test;
 
 #[macro_use]
 mod gas;
@@ -63,7 +66,6 @@ mod wasm;
 mod tests;
 
 use crate::exec::ExecutionContext;
-use crate::account_db::AccountDb;
 
 #[cfg(feature = "std")]
 use serde_derive::{Serialize, Deserialize};
@@ -209,12 +211,17 @@ decl_module! {
 			let loader = crate::wasm::WasmLoader::new(&cfg.schedule);
 			let mut ctx = ExecutionContext::top_level(origin.clone(), &cfg, &vm, &loader);
 
-			let result = ctx.call(dest, value, &mut gas_meter, &data, exec::EmptyOutputBuf::new());
+			let result = match ctx.call(dest, value, &mut gas_meter, &data, exec::EmptyOutputBuf::new()) {
+				// Commit all changes that made it thus far into the persistant storage, provided
+				// they are valid as a whole.
+				Ok(_) => account_db::DirectAccountDb
+					.try_commit(ctx.overlay.into_change_set())
+					.map(|_| ())
+					.map_err(|_| "contract changes could not be committed"),
+				Err(e) => Err(e),
+			};
 
 			if let Ok(_) = result {
-				// Commit all changes that made it thus far into the persistant storage.
-				account_db::DirectAccountDb.commit(ctx.overlay.into_change_set());
-
 				// Then deposit all events produced.
 				ctx.events.into_iter().for_each(Self::deposit_event);
 			}
@@ -225,13 +232,16 @@ decl_module! {
 			// can alter the balance of the caller.
 			gas::refund_unused_gas::<T>(&origin, gas_meter);
 
-			// Dispatch every recorded call with an appropriate origin.
-			ctx.calls.into_iter().for_each(|(who, call)| {
-				let result = call.dispatch(RawOrigin::Signed(who.clone()).into());
-				Self::deposit_event(RawEvent::Dispatched(who, result.is_ok()));
-			});
+			// Dispatch every recorded call with an appropriate origin, unless the changes
+			// couldn't be committed.
+			if let Ok(_) = result {
+				ctx.calls.into_iter().for_each(|(who, call)| {
+					let result = call.dispatch(RawOrigin::Signed(who.clone()).into());
+					Self::deposit_event(RawEvent::Dispatched(who, result.is_ok()));
+				});
+			}
 
-			result.map(|_| ())
+			result
 		}
 
 		/// Create a new contract, optionally transfering some balance to the created account.
@@ -262,12 +272,17 @@ decl_module! {
 			let vm = crate::wasm::WasmVm::new(&cfg.schedule);
 			let loader = crate::wasm::WasmLoader::new(&cfg.schedule);
 			let mut ctx = ExecutionContext::top_level(origin.clone(), &cfg, &vm, &loader);
-			let result = ctx.instantiate(endowment, &mut gas_meter, &code_hash, &data);
+			let result = match ctx.instantiate(endowment, &mut gas_meter, &code_hash, &data) {
+				// Commit all changes that made it thus far into the persistant storage, provided
+				// they are valid as a whole.
+				Ok(_) => account_db::DirectAccountDb
+					.try_commit(ctx.overlay.into_change_set())
+					.map(|_| ())
+					.map_err(|_| "contract changes could not be committed"),
+				Err(e) => Err(e),
+			};
 
 			if let Ok(_) = result {
-				// Commit all changes that made it thus far into the persistant storage.
-				account_db::DirectAccountDb.commit(ctx.overlay.into_change_set());
-
 				// Then deposit all events produced.
 				ctx.events.into_iter().for_each(Self::deposit_event);
 			}
@@ -278,13 +293,16 @@ decl_module! {
 			// can alter the balance of the caller.
 			gas::refund_unused_gas::<T>(&origin, gas_meter);
 
-			// Dispatch every recorded call with an appropriate origin.
-			ctx.calls.into_iter().for_each(|(who, call)| {
-				let result = call.dispatch(RawOrigin::Signed(who.clone()).into());
-				Self::deposit_event(RawEvent::Dispatched(who, result.is_ok()));
-			});
+			// Dispatch every recorded call with an appropriate origin, unless the changes
+			// couldn't be committed.
+			if let Ok(_) = result {
+				ctx.calls.into_iter().for_each(|(who, call)| {
+					let result = call.dispatch(RawOrigin::Signed(who.clone()).into());
+					Self::deposit_event(RawEvent::Dispatched(who, result.is_ok()));
+				});
+			}
 
-			result.map(|_| ())
+			result
 		}
 
 		fn on_finalise() {
@@ -315,6 +333,13 @@ decl_event! {
 		/// A call was dispatched from the given account. The bool signals whether it was
 		/// successful execution or not.
 		Dispatched(AccountId, bool),
+
+		/// A storage entry of the given contract was set to the given value, or removed if `None`.
+		///
+		/// Only deposited if `EmitStorageEvents` is enabled. When a contract account is killed its
+		/// whole storage is removed by `OnFreeBalanceZero` without any of these events, so
+		/// indexers should drop the storage of killed contracts on their own.
+		StorageChanged(AccountId, Vec<u8>, Option<Vec<u8>>),
 	}
 }
 
@@ -336,6 +361,12 @@ decl_storage! {
 		GasSpent get(gas_spent): T::Gas;
 		/// Current cost schedule for contracts.
 		CurrentSchedule get(current_schedule) config(): Schedule<T::Gas> = Schedule::default();
+		/// Whether a `StorageChanged` event is deposited for every committed contract storage write.
+		///
+		/// This is meant for chains serving indexers and is expensive, so it's off by default.
+		EmitStorageEvents get(emit_storage_events) config(): bool = false;
+		/// The maximum size of the storage keys, values and code hashes a call or create may stage.
+		MaxStagedBytes get(max_staged_bytes) config(): u32 = 16 * 1024 * 1024;
 		/// The code associated with a given account.
 		pub CodeHashOf: map T::AccountId => Option<CodeHash<T>>;
 		/// A mapping from an original code hash to the original code, untouched by instrumentation.
@@ -386,6 +417,7 @@ pub struct Config<T: Trait> {
 	pub transfer_fee: T::Balance,
 	pub call_base_fee: T::Gas,
 	pub instantiate_base_fee: T::Gas,
+	pub max_staged_bytes: usize,
 }
 
 impl<T: Trait> Config<T> {
@@ -399,6 +431,7 @@ impl<T: Trait> Config<T> {
 			transfer_fee: <balances::Module<T>>::transfer_fee(),
 			call_base_fee: <Module<T>>::call_base_fee(),
 			instantiate_base_fee: <Module<T>>::create_base_fee(),
+			max_staged_bytes: <Module<T>>::max_staged_bytes() as usize,
 		}
 	}
 }
@@ -32,9 +32,14 @@ use {wabt, balances, consensus};
 use hex_literal::*;
 use assert_matches::assert_matches;
 use crate::{
-	ContractAddressFor, GenesisConfig, Module, RawEvent, StorageOf,
+	CodeHashOf, ContractAddressFor, GenesisConfig, Module, RawEvent, StorageOf,
 	Trait, ComputeDispatchFee
 };
+use crate::account_db::{
+	self, AccountDb, ChangeEntry, ChangeSet, CommitError, DirectAccountDb, OverlayAccountDb,
+	RestoreError, RestrictedCommitError, StagingLimitExceeded,
+};
+use std::collections::{BTreeMap, BTreeSet};
 
 mod contract {
 	// Re-export contents of the root. This basically
@@ -128,6 +133,7 @@ pub struct ExtBuilder {
 	block_gas_limit: u64,
 	transfer_fee: u64,
 	creation_fee: u64,
+	emit_storage_events: bool,
 }
 impl Default for ExtBuilder {
 	fn default() -> Self {
@@ -137,6 +143,7 @@ impl Default for ExtBuilder {
 			block_gas_limit: 100_000_000,
 			transfer_fee: 0,
 			creation_fee: 0,
+			emit_storage_events: false,
 		}
 	}
 }
@@ -161,6 +168,10 @@ impl ExtBuilder {
 		self.creation_fee = creation_fee;
 		self
 	}
+	pub fn emit_storage_events(mut self, emit_storage_events: bool) -> Self {
+		self.emit_storage_events = emit_storage_events;
+		self
+	}
 	pub fn build(self) -> runtime_io::TestExternalities<Blake2Hasher> {
 		let mut t = system::GenesisConfig::<Test>::default()
 			.build_storage()
@@ -187,6 +198,8 @@ impl ExtBuilder {
 				max_depth: 100,
 				block_gas_limit: self.block_gas_limit,
 				current_schedule: Default::default(),
+				emit_storage_events: self.emit_storage_events,
+				max_staged_bytes: 16 * 1024 * 1024,
 			}
 			.build_storage()
 			.unwrap()
@@ -436,3 +449,571 @@ fn dispatch_call() {
 		},
 	);
 }
+
+/// Store the `CODE_RETURN_FROM_START_FN` module and return its hash.
+fn put_return_from_start_code() -> H256 {
+	let wasm = wabt::wat2wasm(CODE_RETURN_FROM_START_FN).unwrap();
+	Balances::set_free_balance(&ALICE, 1_000_000);
+	Balances::increase_total_stake_by(1_000_000);
+	assert_ok!(Contract::put_code(Origin::signed(ALICE), 100_000, wasm));
+	HASH_RETURN_FROM_START_FN.into()
+}
+
+#[test]
+fn restore_checks_tombstone_hash() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let code_hash = put_return_from_start_code();
+		let mut storage = BTreeMap::new();
+		storage.insert(b"foo".to_vec(), b"1".to_vec());
+		storage.insert(b"bar".to_vec(), b"2".to_vec());
+		let tombstone = account_db::tombstone_hash::<Test>(&code_hash, &storage);
+
+		// Restoring with data that doesn't match the tombstone must not stage anything.
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		let mut tampered = storage.clone();
+		tampered.insert(b"bar".to_vec(), b"3".to_vec());
+		assert_eq!(
+			overlay.restore(&BOB, code_hash, tampered, tombstone),
+			Err(RestoreError::TombstoneMismatch)
+		);
+		assert_eq!(overlay.get_code(&BOB), None);
+		assert_eq!(overlay.get_storage(&BOB, b"bar"), None);
+
+		// Matching data is staged as-is.
+		assert_eq!(overlay.restore(&BOB, code_hash, storage, tombstone), Ok(()));
+		assert_eq!(overlay.get_code(&BOB), Some(code_hash));
+		assert_eq!(overlay.get_storage(&BOB, b"foo"), Some(b"1".to_vec()));
+		assert_eq!(overlay.get_storage(&BOB, b"bar"), Some(b"2".to_vec()));
+	});
+}
+
+#[test]
+fn restore_rejects_live_accounts_and_unknown_code() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut storage = BTreeMap::new();
+		storage.insert(b"foo".to_vec(), b"1".to_vec());
+
+		// Nothing was stored under this hash.
+		let unknown_hash = H256::from_low_u64_be(1);
+		let tombstone = account_db::tombstone_hash::<Test>(&unknown_hash, &storage);
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		assert_eq!(
+			overlay.restore(&BOB, unknown_hash, storage.clone(), tombstone),
+			Err(RestoreError::UnknownCodeHash)
+		);
+		assert_eq!(overlay.get_code(&BOB), None);
+
+		let code_hash = put_return_from_start_code();
+		let tombstone = account_db::tombstone_hash::<Test>(&code_hash, &storage);
+		<CodeHashOf<Test>>::insert(&BOB, H256::from_low_u64_be(2));
+		<StorageOf<Test>>::insert(&BOB, &b"bar".to_vec(), b"2".to_vec());
+		assert_eq!(
+			overlay.restore(&BOB, code_hash, storage, tombstone),
+			Err(RestoreError::AccountAlive)
+		);
+		assert_eq!(overlay.get_code(&BOB), Some(H256::from_low_u64_be(2)));
+		assert_eq!(overlay.get_storage(&BOB, b"foo"), None);
+	});
+}
+
+#[test]
+fn change_set_omits_untouched_accounts() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&ALICE, 100);
+
+		// A nested frame that touched BOB but ended up with nothing to write.
+		let mut nested = ChangeSet::<Test>::new();
+		nested.insert(BOB, ChangeEntry::default());
+		overlay.commit(nested);
+
+		let change_set = overlay.into_change_set();
+		assert!(change_set.contains_key(&ALICE));
+		assert!(!change_set.contains_key(&BOB));
+	});
+}
+
+#[test]
+fn try_commit_is_all_or_nothing() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&ALICE, 100);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"1".to_vec())).unwrap();
+		// No code was ever stored under this hash.
+		overlay.set_code(&BOB, Some(H256::from_low_u64_be(1))).unwrap();
+
+		assert_eq!(
+			DirectAccountDb.try_commit(overlay.into_change_set()),
+			Err(CommitError::UnknownCodeHash)
+		);
+
+		// Nothing from the rejected change set must have been written.
+		assert_eq!(Balances::free_balance(&ALICE), 0);
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"foo".to_vec()), None);
+		assert_eq!(<CodeHashOf<Test>>::get(&BOB), None);
+	});
+}
+
+#[test]
+fn commit_notifies_about_new_accounts_once() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&CHARLIE, 100);
+		DirectAccountDb.commit(overlay.into_change_set());
+
+		// Updating an existing account must not register it again.
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&CHARLIE, 150);
+		DirectAccountDb.commit(overlay.into_change_set());
+
+		assert_eq!(System::events(), vec![
+			EventRecord {
+				phase: Phase::ApplyExtrinsic(0),
+				event: MetaEvent::balances(
+					balances::RawEvent::NewAccount(CHARLIE, 100)
+				)
+			},
+		]);
+	});
+}
+
+#[test]
+fn journaled_commit_can_be_undone() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		Balances::set_free_balance(&ALICE, 100);
+		Balances::set_free_balance(&BOB, 10);
+		Balances::increase_total_stake_by(110);
+		<StorageOf<Test>>::insert(&ALICE, &b"foo".to_vec(), b"1".to_vec());
+		<CodeHashOf<Test>>::insert(&ALICE, H256::from_low_u64_be(1));
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&ALICE, 50);
+		overlay.set_balance(&BOB, 50);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), None).unwrap();
+		overlay.set_storage(&ALICE, b"bar".to_vec(), Some(b"2".to_vec())).unwrap();
+		overlay.set_code(&ALICE, Some(H256::from_low_u64_be(2))).unwrap();
+		overlay.set_storage(&BOB, b"baz".to_vec(), Some(b"3".to_vec())).unwrap();
+
+		let journal = DirectAccountDb.commit_with_journal(overlay.into_change_set()).unwrap();
+		assert_eq!(Balances::free_balance(&ALICE), 50);
+		assert_eq!(<StorageOf<Test>>::get(&BOB, &b"baz".to_vec()), Some(b"3".to_vec()));
+
+		// Something went wrong after the commit, roll it back.
+		journal.undo();
+
+		assert_eq!(Balances::free_balance(&ALICE), 100);
+		assert_eq!(Balances::free_balance(&BOB), 10);
+		assert!(<balances::FreeBalance<Test>>::exists(&BOB));
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"foo".to_vec()), Some(b"1".to_vec()));
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"bar".to_vec()), None);
+		assert_eq!(<CodeHashOf<Test>>::get(&ALICE), Some(H256::from_low_u64_be(1)));
+		assert_eq!(<StorageOf<Test>>::get(&BOB, &b"baz".to_vec()), None);
+	});
+}
+
+#[test]
+fn journaled_commit_refuses_to_kill_accounts() {
+	with_externalities(&mut ExtBuilder::default().existential_deposit(100).build(), || {
+		Balances::set_free_balance(&ALICE, 1_000);
+		Balances::increase_total_stake_by(1_000);
+		<StorageOf<Test>>::insert(&ALICE, &b"foo".to_vec(), b"1".to_vec());
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&BOB, 500);
+		// Not zero, but below the existential deposit.
+		overlay.set_balance(&ALICE, 50);
+
+		assert_eq!(
+			DirectAccountDb.commit_with_journal(overlay.into_change_set()).err(),
+			Some(CommitError::WouldKillAccount)
+		);
+
+		// Nothing must have been written, not even the change to BOB.
+		assert_eq!(Balances::free_balance(&ALICE), 1_000);
+		assert_eq!(Balances::free_balance(&BOB), 0);
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"foo".to_vec()), Some(b"1".to_vec()));
+	});
+}
+
+#[test]
+fn journaled_commit_refuses_to_create_accounts() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		Balances::set_free_balance(&ALICE, 100);
+		Balances::increase_total_stake_by(100);
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&ALICE, 50);
+		overlay.set_balance(&BOB, 50);
+
+		assert_eq!(
+			DirectAccountDb.commit_with_journal(overlay.into_change_set()).err(),
+			Some(CommitError::WouldCreateAccount)
+		);
+
+		// BOB must not have been created, and nothing else written either.
+		assert!(!<balances::FreeBalance<Test>>::exists(&BOB));
+		assert_eq!(Balances::free_balance(&ALICE), 100);
+	});
+}
+
+#[test]
+fn get_storage_or_default_decodes() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_storage(&ALICE, b"counter".to_vec(), Some(parity_codec::Encode::encode(&42u32))).unwrap();
+		overlay.set_storage(&ALICE, b"garbage".to_vec(), Some(vec![1])).unwrap();
+
+		assert_eq!(overlay.get_storage_or_default::<u32>(&ALICE, b"absent"), 0);
+		assert_eq!(overlay.get_storage_or_default::<u32>(&ALICE, b"counter"), 42);
+		assert_eq!(overlay.get_storage_or_default::<u32>(&ALICE, b"garbage"), 0);
+		assert_eq!(overlay.get_storage_or(&ALICE, b"garbage", 7u32), 7);
+	});
+}
+
+#[test]
+fn staged_value_is_written_back_only_if_changed() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		{
+			let mut counter = overlay.staged::<u32>(&ALICE, b"counter".to_vec());
+			*counter += 1;
+			*counter += 1;
+		}
+		{
+			// Only read, never changed.
+			let counter = overlay.staged::<u32>(&BOB, b"counter".to_vec());
+			assert_eq!(*counter, 0);
+		}
+
+		assert_eq!(overlay.get_storage_or_default::<u32>(&ALICE, b"counter"), 2);
+		let change_set = overlay.into_change_set();
+		assert!(change_set.contains_key(&ALICE));
+		assert!(!change_set.contains_key(&BOB));
+	});
+}
+
+#[test]
+fn overlay_integrity_check_detects_tampering() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"1".to_vec())).unwrap();
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"2".to_vec())).unwrap();
+		overlay.set_storage(&BOB, b"bar".to_vec(), None).unwrap();
+
+		let mut nested = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		nested.set_storage(&ALICE, b"foo".to_vec(), Some(b"3".to_vec())).unwrap();
+		nested.set_storage(&CHARLIE, b"baz".to_vec(), Some(b"4".to_vec())).unwrap();
+		overlay.commit(nested.into_change_set());
+		assert!(overlay.verify_integrity());
+
+		overlay.tamper_storage(&ALICE, b"foo".to_vec(), Some(b"5".to_vec()));
+		assert!(!overlay.verify_integrity());
+	});
+}
+
+#[test]
+fn get_balances_merges_staged_free_balance() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		Balances::set_free_balance(&ALICE, 50);
+		Balances::set_reserved_balance(&ALICE, 30);
+		Balances::increase_total_stake_by(80);
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		assert_eq!(overlay.get_balances(&ALICE), (50, 30));
+
+		overlay.set_balance(&ALICE, 100);
+		assert_eq!(overlay.get_balances(&ALICE), (100, 30));
+	});
+}
+
+#[test]
+fn overlay_try_commit_rejects_unknown_code() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+
+		let mut nested = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		nested.set_balance(&ALICE, 100);
+		nested.set_code(&BOB, Some(H256::from_low_u64_be(1))).unwrap();
+
+		assert_eq!(overlay.try_commit(nested.into_change_set()), Err(CommitError::UnknownCodeHash));
+		assert!(overlay.into_change_set().is_empty());
+	});
+}
+
+#[test]
+fn commit_restricted_rejects_forbidden_accounts() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		let allowed = [ALICE, BOB].iter().cloned().collect::<BTreeSet<_>>();
+
+		let mut nested = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		nested.set_balance(&ALICE, 50);
+		nested.set_balance(&CHARLIE, 50);
+		assert_eq!(
+			overlay.commit_restricted(nested.into_change_set(), &allowed),
+			Err(RestrictedCommitError::ForbiddenAccount(CHARLIE))
+		);
+		assert!(overlay.into_change_set().is_empty());
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		let mut nested = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		nested.set_balance(&ALICE, 50);
+		nested.set_balance(&BOB, 50);
+		assert_eq!(overlay.commit_restricted(nested.into_change_set(), &allowed), Ok(()));
+		assert_eq!(overlay.get_balance(&BOB), 50);
+	});
+}
+
+#[test]
+fn commit_restricted_respects_staging_limit() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb).with_max_staged_bytes(8);
+		let allowed = [ALICE].iter().cloned().collect::<BTreeSet<_>>();
+
+		let mut nested = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		nested.set_storage(&ALICE, b"foo".to_vec(), Some(vec![0; 6])).unwrap();
+		assert_eq!(
+			overlay.commit_restricted(nested.into_change_set(), &allowed),
+			Err(RestrictedCommitError::StagingLimitExceeded)
+		);
+		assert!(overlay.into_change_set().is_empty());
+	});
+}
+
+#[test]
+fn commit_deposits_storage_events_if_enabled() {
+	with_externalities(&mut ExtBuilder::default().emit_storage_events(true).build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"1".to_vec())).unwrap();
+		overlay.set_storage(&ALICE, b"bar".to_vec(), None).unwrap();
+		overlay.set_storage(&BOB, b"baz".to_vec(), Some(b"2".to_vec())).unwrap();
+		DirectAccountDb.commit(overlay.into_change_set());
+
+		assert_eq!(System::events(), vec![
+			EventRecord {
+				phase: Phase::ApplyExtrinsic(0),
+				event: MetaEvent::contract(RawEvent::StorageChanged(ALICE, b"bar".to_vec(), None)),
+			},
+			EventRecord {
+				phase: Phase::ApplyExtrinsic(0),
+				event: MetaEvent::contract(
+					RawEvent::StorageChanged(ALICE, b"foo".to_vec(), Some(b"1".to_vec()))
+				),
+			},
+			EventRecord {
+				phase: Phase::ApplyExtrinsic(0),
+				event: MetaEvent::contract(
+					RawEvent::StorageChanged(BOB, b"baz".to_vec(), Some(b"2".to_vec()))
+				),
+			},
+		]);
+	});
+
+	// Disabled by default.
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"1".to_vec())).unwrap();
+		DirectAccountDb.commit(overlay.into_change_set());
+
+		assert_eq!(System::events(), vec![]);
+	});
+}
+
+#[test]
+fn undoing_journaled_commit_deposits_storage_events() {
+	with_externalities(&mut ExtBuilder::default().emit_storage_events(true).build(), || {
+		<StorageOf<Test>>::insert(&ALICE, &b"foo".to_vec(), b"1".to_vec());
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"2".to_vec())).unwrap();
+		overlay.set_storage(&ALICE, b"bar".to_vec(), Some(b"3".to_vec())).unwrap();
+		let journal = DirectAccountDb.commit_with_journal(overlay.into_change_set()).unwrap();
+		journal.undo();
+
+		let storage_event = |location: &[u8], value: Option<&[u8]>| EventRecord {
+			phase: Phase::ApplyExtrinsic(0),
+			event: MetaEvent::contract(
+				RawEvent::StorageChanged(ALICE, location.to_vec(), value.map(|v| v.to_vec()))
+			),
+		};
+		assert_eq!(System::events(), vec![
+			storage_event(b"bar", Some(b"3")),
+			storage_event(b"foo", Some(b"2")),
+			// The undo reverts in reverse order.
+			storage_event(b"foo", Some(b"1")),
+			storage_event(b"bar", None),
+		]);
+	});
+}
+
+#[test]
+fn overlay_rejects_staging_beyond_limit() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb).with_max_staged_bytes(50);
+
+		// A staged code hash accounts for its size, 32 bytes for `H256`. Staging exactly up to
+		// the cap is fine.
+		assert_eq!(overlay.set_code(&BOB, Some(H256::from_low_u64_be(1))), Ok(()));
+		assert_eq!(overlay.set_storage(&ALICE, b"ab".to_vec(), Some(vec![0; 16])), Ok(()));
+		assert_eq!(overlay.staged_bytes(), 50);
+		assert_eq!(
+			overlay.set_storage(&ALICE, b"c".to_vec(), Some(vec![0])),
+			Err(StagingLimitExceeded)
+		);
+		assert_eq!(overlay.set_code(&CHARLIE, None), Err(StagingLimitExceeded));
+		assert_eq!(overlay.get_storage(&ALICE, b"c"), None);
+
+		// Overwriting a staged value only accounts for the difference.
+		assert_eq!(overlay.set_storage(&ALICE, b"ab".to_vec(), Some(vec![0; 12])), Ok(()));
+		assert_eq!(overlay.set_code(&BOB, None), Ok(()));
+		assert_eq!(overlay.staged_bytes(), 46);
+
+		// A nested frame rewriting a staged entry is only charged the difference once merged,
+		// even though the new entry alone is bigger than what is left.
+		let change_set = {
+			let mut nested = overlay.nested();
+			assert_eq!(nested.set_storage(&ALICE, b"ab".to_vec(), Some(vec![0; 14])), Ok(()));
+			nested.into_change_set()
+		};
+		assert_eq!(overlay.merge(change_set), Ok(()));
+		assert_eq!(overlay.staged_bytes(), 48);
+
+		// Whether the nested frame fits as a whole is checked when merging it.
+		let change_set = {
+			let mut nested = overlay.nested();
+			assert_eq!(nested.set_storage(&BOB, b"d".to_vec(), Some(vec![0; 2])), Ok(()));
+			nested.into_change_set()
+		};
+		assert_eq!(overlay.merge(change_set), Err(StagingLimitExceeded));
+		assert_eq!(overlay.get_storage(&BOB, b"d"), None);
+		assert_eq!(overlay.staged_bytes(), 48);
+
+		// Change sets that would end up over the limit are rejected as a whole.
+		let over_limit = || {
+			let mut other = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+			other.set_storage(&ALICE, b"ab".to_vec(), None).unwrap();
+			other.set_storage(&CHARLIE, b"e".to_vec(), Some(vec![0; 16])).unwrap();
+			other.into_change_set()
+		};
+		assert_eq!(overlay.merge(over_limit()), Err(StagingLimitExceeded));
+		assert_eq!(overlay.try_commit(over_limit()), Err(CommitError::StagingLimitExceeded));
+		assert_eq!(overlay.get_storage(&ALICE, b"ab"), Some(vec![0; 14]));
+		assert_eq!(overlay.get_storage(&CHARLIE, b"e"), None);
+		assert_eq!(overlay.staged_bytes(), 48);
+	});
+}
+
+#[test]
+fn commit_summary_reports_killed_accounts() {
+	with_externalities(&mut ExtBuilder::default().existential_deposit(100).build(), || {
+		Balances::set_free_balance(&ALICE, 1_000);
+		<StorageOf<Test>>::insert(&ALICE, &b"foo".to_vec(), b"1".to_vec());
+
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		// Not zero, but below the existential deposit.
+		overlay.set_balance(&ALICE, 50);
+		overlay.set_storage(&ALICE, b"bar".to_vec(), Some(b"2".to_vec())).unwrap();
+		overlay.set_balance(&BOB, 500);
+
+		let summary = DirectAccountDb.try_commit(overlay.into_change_set()).unwrap();
+		assert_eq!(summary.killed, vec![ALICE]);
+		assert_eq!(Balances::free_balance(&ALICE), 0);
+		assert_eq!(Balances::free_balance(&BOB), 500);
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"foo".to_vec()), None);
+		assert_eq!(<StorageOf<Test>>::get(&ALICE, &b"bar".to_vec()), None);
+	});
+}
+
+#[test]
+fn change_set_codec_round_trip() {
+	with_externalities(&mut ExtBuilder::default().build(), || {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		overlay.set_balance(&ALICE, 100);
+		overlay.set_storage(&ALICE, b"foo".to_vec(), Some(b"1".to_vec())).unwrap();
+		overlay.set_storage(&ALICE, b"bar".to_vec(), None).unwrap();
+		overlay.set_code(&BOB, Some(H256::from_low_u64_be(1))).unwrap();
+		overlay.set_code(&CHARLIE, None).unwrap();
+		let staged_bytes = overlay.staged_bytes();
+
+		let encoded = account_db::encode_change_set(&overlay.into_change_set());
+		let decoded = account_db::decode_change_set::<Test>(&encoded).unwrap();
+		let rebuilt = OverlayAccountDb::<Test>::from_change_set(&DirectAccountDb, decoded);
+
+		assert_eq!(rebuilt.get_balance(&ALICE), 100);
+		assert_eq!(rebuilt.get_storage(&ALICE, b"foo"), Some(b"1".to_vec()));
+		assert_eq!(rebuilt.get_storage(&ALICE, b"bar"), None);
+		assert_eq!(rebuilt.get_code(&BOB), Some(H256::from_low_u64_be(1)));
+		assert_eq!(rebuilt.staged_bytes(), staged_bytes);
+		// Removals are preserved as well, so the rebuilt overlay stages exactly the same changes.
+		assert_eq!(account_db::encode_change_set(&rebuilt.into_change_set()), encoded);
+
+		assert!(account_db::decode_change_set::<Test>(&encoded[..encoded.len() - 1]).is_none());
+	});
+}
+
+#[cfg(feature = "benchmarks")]
+mod benches {
+	use super::*;
+	use test::Bencher;
+
+	/// Build a change set setting the balance of `accounts` accounts, each with `entries` storage
+	/// values of `value_len` bytes.
+	///
+	/// The benches build it once and only clone it in the timed closure, so they measure the
+	/// commit rather than staging the changes.
+	fn change_set(accounts: u64, entries: u32, value_len: usize, balance: u64) -> ChangeSet<Test> {
+		let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+		for account in 0..accounts {
+			overlay.set_balance(&account, balance);
+			for entry in 0..entries {
+				let location = parity_codec::Encode::encode(&entry);
+				overlay.set_storage(&account, location, Some(vec![0; value_len])).unwrap();
+			}
+		}
+		overlay.into_change_set()
+	}
+
+	#[bench]
+	fn direct_commit_few_accounts_small_values(b: &mut Bencher) {
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let s = change_set(10, 10, 32, 1_000);
+			b.iter(|| DirectAccountDb.commit(s.clone()));
+		});
+	}
+
+	#[bench]
+	fn direct_commit_many_accounts_small_values(b: &mut Bencher) {
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let s = change_set(1_000, 10, 32, 1_000);
+			b.iter(|| DirectAccountDb.commit(s.clone()));
+		});
+	}
+
+	#[bench]
+	fn direct_commit_few_accounts_large_values(b: &mut Bencher) {
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let s = change_set(10, 10, 16 * 1024, 1_000);
+			b.iter(|| DirectAccountDb.commit(s.clone()));
+		});
+	}
+
+	#[bench]
+	fn direct_commit_killed_accounts(b: &mut Bencher) {
+		// Every balance is below the existential deposit, so the storage writes are skipped.
+		with_externalities(&mut ExtBuilder::default().existential_deposit(100).build(), || {
+			let s = change_set(1_000, 10, 32, 50);
+			b.iter(|| DirectAccountDb.commit(s.clone()));
+		});
+	}
+
+	#[bench]
+	fn overlay_commit_many_accounts_small_values(b: &mut Bencher) {
+		with_externalities(&mut ExtBuilder::default().build(), || {
+			let s = change_set(1_000, 10, 32, 1_000);
+			b.iter(|| {
+				let mut overlay = OverlayAccountDb::<Test>::new(&DirectAccountDb);
+				overlay.commit(s.clone());
+			});
+		});
+	}
+}
@@ -212,8 +212,9 @@ mod tests {
 		fn get_storage(&self, key: &[u8]) -> Option<Vec<u8>> {
 			self.storage.get(key).cloned()
 		}
-		fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+		fn set_storage(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), &'static str> {
 			*self.storage.entry(key.to_vec()).or_insert(Vec::new()) = value.unwrap_or(Vec::new());
+			Ok(())
 		}
 		fn instantiate(
 			&mut self,
@@ -220,6 +220,8 @@ define_env!(Env, <E: Ext>,
 	// - value_ptr: pointer into the linear memory
 	//   where the value to set is placed. If `value_non_null` is set to 0, then this parameter is ignored.
 	// - value_len: the length of the value. If `value_non_null` is set to 0, then this parameter is ignored.
+	//
+	// Traps if the change can't be staged because the execution reached its staging limit.
 	ext_set_storage(ctx, key_ptr: u32, value_non_null: u32, value_ptr: u32, value_len: u32) => {
 		let key = read_sandbox_memory(ctx, key_ptr, 32)?;
 		let value =
@@ -228,7 +230,7 @@ define_env!(Env, <E: Ext>,
 			} else {
 				None
 			};
-		ctx.ext.set_storage(&key, value);
+		ctx.ext.set_storage(&key, value).map_err(|_| sandbox::HostError)?;
 
 		Ok(())
 	},